@@ -0,0 +1,115 @@
+//! DWARF `.debug_line` emission.
+//!
+//! `ir::Function::srclocs` associates each instruction with an opaque `SourceLoc`, but
+//! the doc comment on that field is explicit that Cretonne does not interpret it, only
+//! preserves it: turning the locations into something a debugger can use is left to the
+//! embedder. This module does that translation, walking a function's instructions in
+//! layout order alongside their computed code offsets and emitting a standard DWARF
+//! line-number program via the `gimli` write API.
+
+use binemit::CodeOffset;
+use gimli::write::{
+    EndianVec, Error as WriteError, LineProgram, LineString, LineStringTable, Sections,
+    StringTable,
+};
+use gimli::{Encoding, Format, LineEncoding, RunTimeEndian};
+use ir::{Function, SourceLoc};
+use isa::TargetIsa;
+use std::collections::HashMap;
+
+/// A `SourceLoc` resolved to a concrete position in some source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileLocation {
+    /// Index into whatever file table the consumer of the emitted line program keeps.
+    pub file_index: u32,
+    /// 1-based source line.
+    pub line: u32,
+    /// 1-based source column, or `0` if unknown.
+    pub column: u32,
+}
+
+/// Maps the opaque `SourceLoc`s a `Function` carries back to `FileLocation`s.
+///
+/// `SourceLoc` is only a token as far as Cretonne is concerned, so the frontend that
+/// produced it is the only one that knows what file/line/column it denotes. It must
+/// populate this table before `emit_debug_line` can make sense of a function's locations.
+pub type SourceLocMap = HashMap<SourceLoc, FileLocation>;
+
+/// Emit a DWARF `.debug_line` program describing `func`'s instructions.
+///
+/// `func` must already have been through `binemit::relax_branches()`, so that
+/// `func.offsets` (and therefore `Function::inst_offsets`) is populated. Each
+/// instruction whose `SourceLoc` differs from the one before it becomes a new row in the
+/// line program, keyed on its computed code offset; `srcloc_map` supplies the
+/// line/column a `SourceLoc` corresponds to. `FileLocation::file_index` is not used:
+/// every row refers to the program's single placeholder file, `<unknown>`. Instructions
+/// whose location isn't present in `srcloc_map` don't contribute a row.
+///
+/// Returns the encoded bytes of the `.debug_line` section, or an error if the `gimli`
+/// writer rejects the program (for example on a line/file count it can't represent).
+pub fn emit_debug_line(
+    func: &Function,
+    isa: &TargetIsa,
+    srcloc_map: &SourceLocMap,
+) -> Result<Vec<u8>, WriteError> {
+    let encoding = Encoding {
+        address_size: 8,
+        format: Format::Dwarf32,
+        version: 4,
+    };
+    // `LineProgram::new` takes six arguments: `encoding`, `line_encoding`, `working_dir`,
+    // an optional `source_dir`, `source_file`, and an optional `source_file_info`. We
+    // don't track per-compilation-unit source paths or file metadata, so the directory
+    // and file-info slots are filled with placeholders.
+    let mut program = LineProgram::new(
+        encoding,
+        LineEncoding::default(),
+        /* working_dir */ LineString::String(b".".to_vec()),
+        /* source_dir */ None,
+        /* source_file */ LineString::String(b"<unknown>".to_vec()),
+        /* source_file_info */ None,
+    );
+    let file = program.add_file(
+        LineString::String(b"<unknown>".to_vec()),
+        program.default_directory(),
+        None,
+    );
+
+    let encinfo = isa.encoding_info();
+    let mut code_size: CodeOffset = 0;
+    let mut last_loc = None;
+
+    for ebb in func.layout.ebbs() {
+        for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+            code_size = code_size.max(offset + size);
+
+            let loc = func.srclocs[inst];
+            if last_loc == Some(loc) {
+                continue;
+            }
+            last_loc = Some(loc);
+
+            if let Some(resolved) = srcloc_map.get(&loc) {
+                let row = program.row();
+                row.address_offset = u64::from(offset);
+                row.file = file;
+                row.line = u64::from(resolved.line);
+                row.column = u64::from(resolved.column);
+                program.generate_row();
+            }
+        }
+    }
+
+    program.end_sequence(u64::from(code_size));
+
+    let mut sections = Sections::new(EndianVec::new(RunTimeEndian::Little));
+    let mut line_strings = LineStringTable::default();
+    let mut strings = StringTable::default();
+    program.write(
+        &mut sections.debug_line,
+        encoding,
+        &mut line_strings,
+        &mut strings,
+    )?;
+    Ok(sections.debug_line.slice().to_vec())
+}
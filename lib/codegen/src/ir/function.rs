@@ -4,7 +4,7 @@
 //! instructions.
 
 use binemit::CodeOffset;
-use entity::{EntityMap, PrimaryMap};
+use entity::{entity_impl, EntityMap, PrimaryMap};
 use ir;
 use ir::{DataFlowGraph, ExternalName, Layout, Signature};
 use ir::{Ebb, ExtFuncData, FuncRef, GlobalVar, GlobalVarData, Heap, HeapData, JumpTable,
@@ -13,17 +13,60 @@ use ir::{EbbOffsets, InstEncodings, JumpTables, SourceLocs, StackSlots, ValueLoc
 use isa::{EncInfo, Encoding, Legalize, TargetIsa};
 use settings::CallConv;
 use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use write::write_function;
 
-/// A function.
+/// A label attached to an SSA value, identifying the source-level variable it is an
+/// assignment of. Used to track variable locations for debug info.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueLabel(u32);
+entity_impl!(ValueLabel, "val_label");
+
+/// The starting point of a `ValueLabel` assignment to the SSA value it is attached to in
+/// `FunctionParameters::value_labels`.
 ///
-/// Functions can be cloned, but it is not a very fast operation.
-/// The clone will have all the same entity numbers as the original.
-#[derive(Clone)]
-pub struct Function {
-    /// Name of this function. Mostly used by `.cton` files.
-    pub name: ExternalName,
+/// The assignment is understood to hold from `from_offset`, expressed as a code offset
+/// from the start of the function, until either the next `ValueLabelStart` recorded for
+/// the same label or the end of the function, whichever comes first.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ValueLabelStart {
+    /// The code offset at which this assignment starts.
+    pub from_offset: CodeOffset,
+
+    /// The source variable this value is an assignment of.
+    pub label: ValueLabel,
+}
 
+/// Mapping from SSA values to the source variables they hold at various points in the
+/// function, for debug info purposes. See `Function::set_value_label`.
+pub type ValueLabels = EntityMap<ir::Value, Vec<ValueLabelStart>>;
+
+/// A placeholder standing in for an `ExternalName` inside a `FunctionStencil`.
+///
+/// The stencil never stores concrete external names directly; it only stores
+/// `ExternalNameRef`s, which are resolved back to an `ExternalName` through the
+/// `FunctionParameters` that accompany it. This indirection is what lets two functions
+/// that reference different external symbols, but are otherwise identical, share the
+/// same stencil.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExternalNameRef(u32);
+entity_impl!(ExternalNameRef, "extname");
+
+/// The compilation-relevant contents of a `Function`.
+///
+/// This holds everything that determines the machine code `binemit` will produce for a
+/// function, and nothing else: no function name, and no concrete external names or
+/// relocation targets (those are kept in a separate `FunctionParameters`, addressed
+/// through `ExternalNameRef` placeholders). Each function keeps its own
+/// `FunctionParameters` for recovering the final symbol names.
+///
+/// This does *not* implement `PartialEq`/`Eq`/`Hash`, so stencils cannot be compared or
+/// hashed, and no cache key is derived from them here. `dfg` and `layout` don't implement
+/// those traits (their interned value/instruction numbering isn't structural equality),
+/// so a blanket derive wouldn't compile.
+#[derive(Clone)]
+pub struct FunctionStencil {
     /// Signature of this function.
     pub signature: Signature,
 
@@ -46,6 +89,11 @@ pub struct Function {
     /// Data flow graph containing the primary definition of all instructions, EBBs and values.
     pub dfg: DataFlowGraph,
 
+    /// The `ExternalNameRef` placeholder each external function import's name was
+    /// recorded under in `FunctionParameters`, keyed by the same `FuncRef` as
+    /// `dfg.ext_funcs`. See `Function::import_function`.
+    pub ext_func_names: EntityMap<FuncRef, ExternalNameRef>,
+
     /// Layout of EBBs and instructions in the function body.
     pub layout: Layout,
 
@@ -70,18 +118,18 @@ pub struct Function {
     pub srclocs: SourceLocs,
 }
 
-impl Function {
-    /// Create a function with the given name and signature.
-    pub fn with_name_signature(name: ExternalName, sig: Signature) -> Self {
+impl FunctionStencil {
+    /// Create a stencil for a function with the given signature, with everything else empty.
+    fn with_signature(signature: Signature) -> Self {
         Self {
-            name,
-            signature: sig,
+            signature,
             stack_slots: StackSlots::new(),
             stack_limit: None,
             global_vars: PrimaryMap::new(),
             heaps: PrimaryMap::new(),
             jump_tables: PrimaryMap::new(),
             dfg: DataFlowGraph::new(),
+            ext_func_names: EntityMap::new(),
             layout: Layout::new(),
             encodings: EntityMap::new(),
             locations: EntityMap::new(),
@@ -90,20 +138,117 @@ impl Function {
         }
     }
 
-    /// Clear all data structures in this function.
-    pub fn clear(&mut self) {
+    /// Clear all data structures in this stencil.
+    fn clear(&mut self) {
         self.signature.clear(CallConv::Fast);
         self.stack_slots.clear();
         self.global_vars.clear();
         self.heaps.clear();
         self.jump_tables.clear();
         self.dfg.clear();
+        self.ext_func_names.clear();
         self.layout.clear();
         self.encodings.clear();
         self.locations.clear();
         self.offsets.clear();
         self.srclocs.clear();
     }
+}
+
+/// The name-, relocation- and debug-only data of a `Function`.
+///
+/// None of this affects the code `binemit` generates for the function it accompanies, so
+/// it is kept out of the `FunctionStencil`.
+/// External names referenced from the stencil go through an `ExternalNameRef`
+/// placeholder that is resolved back to a concrete `ExternalName` here, and source
+/// variable labels -- which a debugger consults, not `binemit` -- live here too.
+#[derive(Clone, Default)]
+pub struct FunctionParameters {
+    external_names: PrimaryMap<ExternalNameRef, ExternalName>,
+    value_labels: ValueLabels,
+}
+
+impl FunctionParameters {
+    /// Create an empty set of parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `name`, returning a placeholder the stencil can store in its place.
+    pub fn ensure_name_included(&mut self, name: ExternalName) -> ExternalNameRef {
+        self.external_names.push(name)
+    }
+
+    /// Look up the concrete external name a placeholder stands for.
+    pub fn name(&self, reference: ExternalNameRef) -> &ExternalName {
+        &self.external_names[reference]
+    }
+
+    /// Records that `value` holds the source variable `label`, starting at `from_offset`
+    /// (a code offset from the start of the function).
+    pub fn set_value_label(&mut self, value: ir::Value, label: ValueLabel, from_offset: CodeOffset) {
+        self.value_labels[value].push(ValueLabelStart { from_offset, label });
+    }
+
+    /// Transfers `from`'s value-label assignments over to `to`.
+    ///
+    /// Legalization and register allocation can split or coalesce SSA values, which would
+    /// otherwise orphan any `ValueLabel`s attached to them. Whenever `from` is replaced by
+    /// `to`, call this to keep the variable-location map accurate.
+    pub fn transfer_value_label(&mut self, from: ir::Value, to: ir::Value) {
+        let labels = mem::replace(&mut self.value_labels[from], Vec::new());
+        self.value_labels[to].extend(labels);
+    }
+}
+
+/// A function.
+///
+/// Functions can be cloned, but it is not a very fast operation.
+/// The clone will have all the same entity numbers as the original.
+#[derive(Clone)]
+pub struct Function {
+    /// Name of this function. Mostly used by `.cton` files.
+    pub name: ExternalName,
+
+    /// The compilation-relevant contents of this function. It cannot be compared or
+    /// hashed: `FunctionStencil` implements none of `PartialEq`, `Eq` or `Hash`.
+    pub stencil: FunctionStencil,
+
+    /// The name-, relocation- and debug-only data that accompanies `stencil`: it resolves
+    /// its `ExternalNameRef` placeholders back to concrete external names and holds the
+    /// value labels.
+    pub params: FunctionParameters,
+}
+
+impl Deref for Function {
+    type Target = FunctionStencil;
+
+    fn deref(&self) -> &FunctionStencil {
+        &self.stencil
+    }
+}
+
+impl DerefMut for Function {
+    fn deref_mut(&mut self) -> &mut FunctionStencil {
+        &mut self.stencil
+    }
+}
+
+impl Function {
+    /// Create a function with the given name and signature.
+    pub fn with_name_signature(name: ExternalName, sig: Signature) -> Self {
+        Self {
+            name,
+            stencil: FunctionStencil::with_signature(sig),
+            params: FunctionParameters::new(),
+        }
+    }
+
+    /// Clear all data structures in this function.
+    pub fn clear(&mut self) {
+        self.stencil.clear();
+        self.params = FunctionParameters::new();
+    }
 
     /// Create a new empty, anonymous function with a Fast calling convention.
     pub fn new() -> Self {
@@ -141,8 +286,31 @@ impl Function {
     }
 
     /// Declare an external function import.
+    ///
+    /// `data.name` is recorded in `self.params` through an `ExternalNameRef` placeholder,
+    /// and `dfg.ext_funcs` only ever sees a name-independent sentinel in its place: the
+    /// real name lives solely in `params`, addressed by the placeholder this function
+    /// stores in `ext_func_names`. Two functions that import the same shape of external
+    /// functions, differing only in which concrete symbols they name, therefore produce
+    /// identical `dfg` content for this import -- use `Function::imported_name` to
+    /// recover the real name.
     pub fn import_function(&mut self, data: ExtFuncData) -> FuncRef {
-        self.dfg.ext_funcs.push(data)
+        let name_ref = self.params.ensure_name_included(data.name);
+        let data = ExtFuncData {
+            name: ExternalName::default(),
+            ..data
+        };
+        let func_ref = self.dfg.ext_funcs.push(data);
+        self.ext_func_names[func_ref] = name_ref;
+        func_ref
+    }
+
+    /// Look up the concrete external name `func_ref` was imported under.
+    ///
+    /// This is the real name that `import_function` recorded in `params`; the name
+    /// stored in `dfg.ext_funcs[func_ref]` itself is only a name-independent sentinel.
+    pub fn imported_name(&self, func_ref: FuncRef) -> &ExternalName {
+        self.params.name(self.ext_func_names[func_ref])
     }
 
     /// Declares a global variable accessible to the function.
@@ -160,6 +328,29 @@ impl Function {
         DisplayFunction(self, isa.into())
     }
 
+    /// Return an object that displays this function's IR interleaved with its encoded
+    /// machine code: each instruction's line is prefixed by its code offset and the hex
+    /// of the `size` bytes it occupies in `code`, mirroring how a `binemit` text sink
+    /// lays out its offset/bytes/mnemonic columns. Passing a `disasm` additionally
+    /// decodes those bytes and prints the native mnemonic alongside the Cretonne
+    /// instruction, which makes it easy to spot encoding bugs.
+    ///
+    /// `code` must be the machine code this function's `offsets`, as computed by
+    /// `binemit::relax_branches()`, refer into.
+    pub fn display_with_bytes<'a>(
+        &'a self,
+        isa: &'a TargetIsa,
+        code: &'a [u8],
+        disasm: Option<&'a Disassembler>,
+    ) -> DisplayFunctionAnnotated<'a> {
+        DisplayFunctionAnnotated {
+            func: self,
+            isa,
+            code,
+            disasm,
+        }
+    }
+
     /// Find a presumed unique special-purpose function parameter value.
     ///
     /// Returns the value of the last `purpose` parameter, or `None` if no such parameter exists.
@@ -202,6 +393,21 @@ impl Function {
     pub fn encode(&self, inst: ir::Inst, isa: &TargetIsa) -> Result<Encoding, Legalize> {
         isa.encode(&self, &self.dfg[inst], self.dfg.ctrl_typevar(inst))
     }
+
+    /// Records that `value` holds the source variable `label`, starting at `from_offset`
+    /// (a code offset from the start of the function).
+    pub fn set_value_label(&mut self, value: ir::Value, label: ValueLabel, from_offset: CodeOffset) {
+        self.params.set_value_label(value, label, from_offset);
+    }
+
+    /// Transfers `from`'s value-label assignments over to `to`.
+    ///
+    /// Legalization and register allocation can split or coalesce SSA values, which would
+    /// otherwise orphan any `ValueLabel`s attached to them. Whenever `from` is replaced by
+    /// `to`, call this to keep the variable-location map accurate.
+    pub fn transfer_value_label(&mut self, from: ir::Value, to: ir::Value) {
+        self.params.transfer_value_label(from, to);
+    }
 }
 
 /// Wrapper type capable of displaying a `Function` with correct ISA annotations.
@@ -213,6 +419,69 @@ impl<'a> fmt::Display for DisplayFunction<'a> {
     }
 }
 
+/// A decoder from encoded machine-code bytes to a native disassembly string.
+///
+/// `display_with_bytes` takes one of these per target ISA so it can show the decoded
+/// native mnemonic next to the Cretonne instruction it was encoded from.
+pub trait Disassembler {
+    /// Decode the single instruction found at the start of `bytes`, which holds exactly
+    /// that instruction's encoded size, and return its textual disassembly.
+    fn disassemble(&self, bytes: &[u8]) -> String;
+}
+
+/// Wrapper type capable of displaying a `Function` interleaved with the machine code it
+/// was encoded into. See `Function::display_with_bytes`.
+pub struct DisplayFunctionAnnotated<'a> {
+    func: &'a Function,
+    isa: &'a TargetIsa,
+    code: &'a [u8],
+    disasm: Option<&'a Disassembler>,
+}
+
+/// Upper bound on the column width the hex dump in `DisplayFunctionAnnotated` pads to.
+///
+/// No supported ISA encodes an instruction longer than this (x86's worst case is 15
+/// bytes), so padding to it keeps the mnemonic column aligned across every row instead
+/// of only across rows no longer than 8 bytes.
+const MAX_INST_BYTES: usize = 16;
+
+impl<'a> fmt::Display for DisplayFunctionAnnotated<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let encinfo = self.isa.encoding_info();
+        for ebb in self.func.layout.ebbs() {
+            writeln!(fmt, "{}:", ebb)?;
+            for (offset, inst, size) in self.func.inst_offsets(ebb, &encinfo) {
+                let start = offset as usize;
+                let end = start + size as usize;
+                let bytes = self.code.get(start..end).unwrap_or_else(|| {
+                    panic!(
+                        "`code` ({} bytes) does not cover the range {}..{} that \
+                         `offsets` claims for {}; was it computed for this `code`?",
+                        self.code.len(),
+                        start,
+                        end,
+                        inst
+                    )
+                });
+
+                write!(fmt, "{:8x}  ", offset)?;
+                for byte in bytes {
+                    write!(fmt, "{:02x} ", byte)?;
+                }
+                for _ in bytes.len()..MAX_INST_BYTES {
+                    write!(fmt, "   ")?;
+                }
+                write!(fmt, " {}", self.func.dfg.display_inst(inst, Some(self.isa)))?;
+                if let Some(disasm) = self.disasm {
+                    write!(fmt, "  ; {}", disasm.disassemble(bytes))?;
+                }
+                writeln!(fmt)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for Function {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write_function(fmt, self, None)
@@ -0,0 +1,5 @@
+//! Cretonne code generator library.
+
+pub mod debug;
+pub mod ir;
+pub mod print_errors;
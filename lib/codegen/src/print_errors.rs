@@ -7,21 +7,69 @@ use std::fmt::Write;
 use std::string::{String, ToString};
 use verifier::VerifierError;
 
+/// Options controlling how `pretty_error_context` pinpoints an error within a function.
+#[derive(Clone, Copy, Debug)]
+pub struct PrettyErrorOptions {
+    /// Number of instructions to show before and after the offending one, within its
+    /// EBB. `None` means only the offending instruction itself is printed.
+    pub context_radius: Option<usize>,
+
+    /// Whether to also dump the full function text after the windowed context. Turning
+    /// this off is useful on large functions, where the surrounding window is already
+    /// enough to diagnose the error.
+    pub include_function: bool,
+
+    /// Whether to show each instruction's code offset in a gutter column. Only has an
+    /// effect when `func.offsets` has already been populated by
+    /// `binemit::relax_branches()` and an `isa` is given.
+    pub show_offsets: bool,
+}
+
+impl Default for PrettyErrorOptions {
+    fn default() -> Self {
+        Self {
+            context_radius: Some(2),
+            include_function: true,
+            show_offsets: false,
+        }
+    }
+}
+
 /// Pretty-print a verifier error.
 pub fn pretty_verifier_error(
     func: &ir::Function,
     isa: Option<&TargetIsa>,
     err: &VerifierError,
 ) -> String {
-    let mut msg = err.to_string();
-    match err.location {
-        ir::entities::AnyEntity::Inst(inst) => {
-            write!(msg, "\n{}: {}\n\n", inst, func.dfg.display_inst(inst, isa)).unwrap()
-        }
-        _ => msg.push('\n'),
-    }
-    write!(msg, "{}", func.display(isa)).unwrap();
-    msg
+    pretty_error_context(
+        func,
+        isa,
+        err.location,
+        &err.to_string(),
+        PrettyErrorOptions::default(),
+    )
+}
+
+/// Pretty-print a `binemit` encoding error, pinpointing `inst` with the same windowed
+/// formatter `pretty_verifier_error` uses. Offsets are shown in the gutter when `isa` is
+/// given, since a `binemit` error implies `func.offsets` has already been computed.
+pub fn pretty_binemit_error(
+    func: &ir::Function,
+    isa: Option<&TargetIsa>,
+    inst: ir::Inst,
+    message: &str,
+) -> String {
+    let options = PrettyErrorOptions {
+        show_offsets: true,
+        ..PrettyErrorOptions::default()
+    };
+    pretty_error_context(
+        func,
+        isa,
+        ir::entities::AnyEntity::Inst(inst),
+        message,
+        options,
+    )
 }
 
 /// Pretty-print a Cretonne error.
@@ -32,3 +80,99 @@ pub fn pretty_error(func: &ir::Function, isa: Option<&TargetIsa>, err: CodegenEr
         err.to_string()
     }
 }
+
+/// Shared formatter behind `pretty_verifier_error` and `pretty_binemit_error`.
+///
+/// When `location` names an instruction, prints a windowed view of the EBB containing
+/// it -- the EBB header plus `options.context_radius` instructions on either side --
+/// with a gutter of code offsets if `options.show_offsets` is set, `func.offsets` is
+/// populated and `isa` is given, and a `>` marker on the offending instruction. `options.include_function`
+/// controls whether the full function text follows.
+fn pretty_error_context(
+    func: &ir::Function,
+    isa: Option<&TargetIsa>,
+    location: ir::entities::AnyEntity,
+    message: &str,
+    options: PrettyErrorOptions,
+) -> String {
+    let mut msg = message.to_string();
+    match location {
+        ir::entities::AnyEntity::Inst(inst) => {
+            msg.push('\n');
+            write_windowed_context(&mut msg, func, isa, inst, options);
+            msg.push('\n');
+        }
+        _ => msg.push('\n'),
+    }
+    if options.include_function {
+        write!(msg, "{}", func.display(isa)).unwrap();
+    }
+    msg
+}
+
+/// Append a windowed view of `inst`'s EBB to `msg`, per `options`.
+fn write_windowed_context(
+    msg: &mut String,
+    func: &ir::Function,
+    isa: Option<&TargetIsa>,
+    inst: ir::Inst,
+    options: PrettyErrorOptions,
+) {
+    // Not every instruction an error can point at is necessarily in the layout (it may
+    // have been detached during an earlier verifier pass, for example), so fall back to
+    // printing it on its own rather than panicking on the EBB/position lookups below.
+    let ebb = match func.layout.inst_ebb(inst) {
+        Some(ebb) => ebb,
+        None => {
+            writeln!(msg, "{}: {}", inst, func.dfg.display_inst(inst, isa)).unwrap();
+            return;
+        }
+    };
+    let insts: Vec<ir::Inst> = func.layout.ebb_insts(ebb).collect();
+    let pos = match insts.iter().position(|&candidate| candidate == inst) {
+        Some(pos) => pos,
+        None => {
+            writeln!(msg, "{}: {}", inst, func.dfg.display_inst(inst, isa)).unwrap();
+            return;
+        }
+    };
+
+    let offsets = if options.show_offsets && !func.offsets.is_empty() {
+        isa.map(|isa| {
+            let encinfo = isa.encoding_info();
+            func.inst_offsets(ebb, &encinfo)
+                .map(|(offset, i, _)| (i, offset))
+                .collect::<Vec<_>>()
+        })
+    } else {
+        None
+    };
+
+    writeln!(msg, "{}:", ebb).unwrap();
+
+    let (start, end) = match options.context_radius {
+        Some(radius) => (
+            pos.saturating_sub(radius),
+            (pos + radius + 1).min(insts.len()),
+        ),
+        None => (pos, pos + 1),
+    };
+
+    for (i, &window_inst) in insts[start..end].iter().enumerate() {
+        let marker = if start + i == pos { "> " } else { "  " };
+        write!(msg, "{}", marker).unwrap();
+        if let Some(offsets) = &offsets {
+            let offset = offsets
+                .iter()
+                .find(|&&(i, _)| i == window_inst)
+                .map_or(0, |&(_, offset)| offset);
+            write!(msg, "{:8x}  ", offset).unwrap();
+        }
+        writeln!(
+            msg,
+            "{}: {}",
+            window_inst,
+            func.dfg.display_inst(window_inst, isa)
+        ).unwrap();
+    }
+}